@@ -0,0 +1,371 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::cmp::Reverse;
+use std::fmt;
+
+use crate::types::{FeeRate, UTXO};
+
+/// Rough weight, in vbytes, of spending a single `P2WPKH` input. Used to estimate the marginal
+/// cost of adding a UTXO to a transaction until coin selection is descriptor-aware.
+const INPUT_VBYTES: f32 = 68.0;
+/// Rough weight, in vbytes, of a single `P2WPKH` change output.
+const CHANGE_OUTPUT_VBYTES: f32 = 31.0;
+
+/// The result of a [`CoinSelectionAlgorithm`] pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinSelectionResult {
+    pub selected: Vec<UTXO>,
+    pub fee_amount: u64,
+    /// Whether the selected value exceeds the target enough that the caller should add a
+    /// change output for the leftover.
+    pub requires_change: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoinSelectionError {
+    InsufficientFunds { needed: u64, available: u64 },
+}
+
+impl fmt::Display for CoinSelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoinSelectionError::InsufficientFunds { needed, available } => write!(
+                f,
+                "insufficient funds: {} sat needed, {} sat available",
+                needed, available
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CoinSelectionError {}
+
+fn input_fee(fee_rate: FeeRate) -> i64 {
+    (INPUT_VBYTES * fee_rate.as_sat_vb()) as i64
+}
+
+/// A strategy for picking which UTXOs fund a transaction.
+pub trait CoinSelectionAlgorithm: std::fmt::Debug {
+    /// Select UTXOs from `utxos` that, at `fee_rate`, cover `amount_needed` plus `fixed_fee`
+    /// (the fee already owed for the recipient outputs and transaction overhead).
+    fn coin_select(
+        &self,
+        utxos: Vec<UTXO>,
+        fee_rate: FeeRate,
+        amount_needed: u64,
+        fixed_fee: u64,
+    ) -> Result<CoinSelectionResult, CoinSelectionError>;
+}
+
+/// Selects UTXOs in random order until the target amount is covered.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SingleRandomDraw;
+
+impl CoinSelectionAlgorithm for SingleRandomDraw {
+    fn coin_select(
+        &self,
+        mut utxos: Vec<UTXO>,
+        fee_rate: FeeRate,
+        amount_needed: u64,
+        fixed_fee: u64,
+    ) -> Result<CoinSelectionResult, CoinSelectionError> {
+        use rand::seq::SliceRandom;
+        #[cfg(test)]
+        use rand::SeedableRng;
+
+        #[cfg(not(test))]
+        let mut rng = rand::thread_rng();
+        #[cfg(test)]
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+
+        utxos.shuffle(&mut rng);
+
+        let target = amount_needed + fixed_fee;
+        let mut selected = Vec::new();
+        let mut selected_value = 0u64;
+        let mut fee_amount = fixed_fee;
+
+        for utxo in utxos {
+            if selected_value >= target {
+                break;
+            }
+
+            fee_amount += input_fee(fee_rate).max(0) as u64;
+            selected_value += utxo.txout.value;
+            selected.push(utxo);
+        }
+
+        if selected_value < target {
+            return Err(CoinSelectionError::InsufficientFunds {
+                needed: target,
+                available: selected_value,
+            });
+        }
+
+        Ok(CoinSelectionResult {
+            requires_change: selected_value > target,
+            fee_amount,
+            selected,
+        })
+    }
+}
+
+pub type DefaultCoinSelectionAlgorithm = SingleRandomDraw;
+
+/// A changeless-first coin selection algorithm based on the Branch and Bound search described
+/// for Bitcoin Core's wallet (Erhardt, "An Evaluation of Coin Selection Strategies").
+///
+/// Candidates are ranked by *effective value* (their value minus the fee of spending them at
+/// the current fee rate) and explored depth-first, branching on include/exclude for each UTXO,
+/// to find a changeless combination that lands within `cost_of_change` of the target. If no
+/// such combination is found within [`MAX_ITERATIONS`](Self::MAX_ITERATIONS) tries, selection
+/// falls back to [`SingleRandomDraw`] so that a transaction (with a change output) can still be
+/// built.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BranchAndBoundCoinSelection;
+
+impl BranchAndBoundCoinSelection {
+    /// Upper bound on the number of search states explored before giving up and falling back
+    /// to [`SingleRandomDraw`].
+    const MAX_ITERATIONS: usize = 100_000;
+
+    fn cost_of_change(fee_rate: FeeRate) -> u64 {
+        ((CHANGE_OUTPUT_VBYTES + INPUT_VBYTES) * fee_rate.as_sat_vb()) as u64
+    }
+
+    /// Depth-first search over `candidates` (already sorted by descending effective value) for
+    /// the subset whose effective value sum falls in `target..=target + cost_of_change`, with
+    /// the smallest such sum (least waste) winning. Returns indices into `candidates`.
+    fn search(candidates: &[(UTXO, i64)], target: u64, cost_of_change: u64) -> Option<Vec<usize>> {
+        let target = target as i64;
+        let upper_bound = target + cost_of_change as i64;
+
+        let mut remaining_sum = vec![0i64; candidates.len() + 1];
+        for i in (0..candidates.len()).rev() {
+            remaining_sum[i] = remaining_sum[i + 1] + candidates[i].1;
+        }
+
+        let mut best: Option<(Vec<usize>, i64)> = None;
+        let mut iterations = 0usize;
+        let mut stack: Vec<(usize, i64, Vec<usize>)> = vec![(0, 0, Vec::new())];
+
+        while let Some((index, running_sum, selected)) = stack.pop() {
+            iterations += 1;
+            if iterations > Self::MAX_ITERATIONS {
+                break;
+            }
+
+            if running_sum > upper_bound {
+                continue; // overshoot: this branch can only get worse, prune it
+            }
+            if running_sum >= target {
+                let waste = running_sum - target;
+                let is_better = match &best {
+                    Some((_, best_waste)) => waste < *best_waste,
+                    None => true,
+                };
+                if is_better {
+                    best = Some((selected, waste));
+                }
+                continue;
+            }
+            if running_sum + remaining_sum[index] < target {
+                continue; // even taking everything left can't reach the target, prune it
+            }
+            if index == candidates.len() {
+                continue;
+            }
+
+            // Explore both branches: skip `candidates[index]`, and include it.
+            stack.push((index + 1, running_sum, selected.clone()));
+
+            let mut with_candidate = selected;
+            with_candidate.push(index);
+            stack.push((index + 1, running_sum + candidates[index].1, with_candidate));
+        }
+
+        best.map(|(selected, _)| selected)
+    }
+}
+
+impl CoinSelectionAlgorithm for BranchAndBoundCoinSelection {
+    fn coin_select(
+        &self,
+        utxos: Vec<UTXO>,
+        fee_rate: FeeRate,
+        amount_needed: u64,
+        fixed_fee: u64,
+    ) -> Result<CoinSelectionResult, CoinSelectionError> {
+        let target = amount_needed + fixed_fee;
+        let cost_of_change = Self::cost_of_change(fee_rate);
+
+        let mut candidates: Vec<(UTXO, i64)> = utxos
+            .iter()
+            .cloned()
+            .filter_map(|utxo| {
+                let effective_value = utxo.txout.value as i64 - input_fee(fee_rate);
+                if effective_value > 0 {
+                    Some((utxo, effective_value))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        candidates.sort_unstable_by_key(|(_, effective_value)| Reverse(*effective_value));
+
+        if let Some(selected) = Self::search(&candidates, target, cost_of_change) {
+            // A BnB match is, by construction, within `cost_of_change` of the target: that
+            // small overshoot ("waste") is absorbed into the fee instead of becoming a change
+            // output, which is the whole point of changeless selection. Asking the caller to
+            // add a change output here would just recreate the dust change BnB exists to avoid.
+            let selected_value: i64 = selected.iter().map(|&i| candidates[i].1).sum();
+            let waste = (selected_value - target as i64).max(0) as u64;
+            return Ok(CoinSelectionResult {
+                requires_change: false,
+                fee_amount: fixed_fee
+                    + selected.len() as u64 * input_fee(fee_rate).max(0) as u64
+                    + waste,
+                selected: selected
+                    .into_iter()
+                    .map(|i| candidates[i].0.clone())
+                    .collect(),
+            });
+        }
+
+        // No changeless combination was found within the iteration budget: fall back to the
+        // single-random-draw selection so a transaction can still be built, at the cost of a
+        // change output. Fall back on the original, unfiltered UTXO set: `candidates` dropped
+        // any UTXO whose effective value wasn't strictly positive, but such a UTXO can still be
+        // spent as part of a change-producing transaction (it just can't fund itself).
+        SingleRandomDraw.coin_select(utxos, fee_rate, amount_needed, fixed_fee)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{OutPoint, TxOut};
+
+    use super::*;
+
+    fn utxo(value: u64) -> UTXO {
+        UTXO {
+            outpoint: OutPoint::default(),
+            txout: TxOut {
+                value,
+                script_pubkey: Default::default(),
+            },
+            is_internal: false,
+        }
+    }
+
+    #[test]
+    fn test_bnb_finds_changeless_combination() {
+        // A zero fee rate makes effective value equal to face value, so an exact
+        // 50_300 + 25_000 = 75_300 match (covering the fixed fee too) is reachable.
+        let utxos = vec![utxo(100_000), utxo(50_300), utxo(25_000)];
+        let result = BranchAndBoundCoinSelection
+            .coin_select(utxos, FeeRate::from_sat_per_vb(0.0), 75_000, 300)
+            .unwrap();
+
+        let selected_value: u64 = result.selected.iter().map(|u| u.txout.value).sum();
+        assert_eq!(selected_value, 75_300);
+        assert!(!result.requires_change);
+    }
+
+    #[test]
+    fn test_bnb_match_with_nonzero_fee_rate_is_changeless() {
+        // With a non-zero fee rate, a BnB match lands within `cost_of_change` of the target
+        // rather than hitting it exactly (50_300 + 25_150, minus two inputs' fees, overshoots
+        // the 75_300 target by 14 sat); that small overshoot must be absorbed as fee, not
+        // reported as needing a (dust) change output.
+        let utxos = vec![utxo(100_000), utxo(50_300), utxo(25_150)];
+        let result = BranchAndBoundCoinSelection
+            .coin_select(utxos, FeeRate::from_sat_per_vb(1.0), 75_000, 300)
+            .unwrap();
+
+        let selected_value: u64 = result.selected.iter().map(|u| u.txout.value).sum();
+        assert_eq!(selected_value, 75_450);
+        assert!(!result.requires_change);
+        // fixed_fee (300) + 2 inputs' fees (2 * 68) + the 14 sat of overshoot absorbed as fee.
+        assert_eq!(result.fee_amount, 300 + 2 * 68 + 14);
+    }
+
+    #[test]
+    fn test_bnb_falls_back_to_single_random_draw() {
+        // No subset of these UTXOs can cover the target without leaving change.
+        let utxos = vec![utxo(200_000), utxo(80_000)];
+        let result = BranchAndBoundCoinSelection
+            .coin_select(utxos, FeeRate::from_sat_per_vb(1.0), 75_000, 300)
+            .unwrap();
+
+        let selected_value: u64 = result.selected.iter().map(|u| u.txout.value).sum();
+        assert!(selected_value >= 75_300);
+        assert!(result.requires_change);
+    }
+
+    #[test]
+    fn test_bnb_fallback_can_use_a_dust_utxo() {
+        // `utxo(50)` has a negative effective value at this fee rate (50 - 68 < 0), so BnB
+        // excludes it from candidates and can't find a changeless match with just `utxo(60_000)`
+        // either (60_000 < 60_040). The fallback must still be able to spend both of the
+        // original UTXOs together to cover the target, even though one of them is dust on its
+        // own.
+        let utxos = vec![utxo(60_000), utxo(50)];
+        let result = BranchAndBoundCoinSelection
+            .coin_select(utxos, FeeRate::from_sat_per_vb(1.0), 60_040, 0)
+            .unwrap();
+
+        let selected_value: u64 = result.selected.iter().map(|u| u.txout.value).sum();
+        assert_eq!(selected_value, 60_050);
+        assert!(result.requires_change);
+    }
+
+    #[test]
+    fn test_bnb_errors_on_insufficient_funds() {
+        let utxos = vec![utxo(1_000)];
+        let result = BranchAndBoundCoinSelection.coin_select(
+            utxos,
+            FeeRate::from_sat_per_vb(1.0),
+            75_000,
+            300,
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_single_random_draw_is_deterministic_under_test() {
+        let utxos = vec![utxo(100_000), utxo(50_000), utxo(25_000)];
+
+        let a = SingleRandomDraw
+            .coin_select(utxos.clone(), FeeRate::from_sat_per_vb(1.0), 30_000, 300)
+            .unwrap();
+        let b = SingleRandomDraw
+            .coin_select(utxos, FeeRate::from_sat_per_vb(1.0), 30_000, 300)
+            .unwrap();
+
+        assert_eq!(a.selected, b.selected);
+    }
+}
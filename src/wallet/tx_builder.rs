@@ -24,12 +24,17 @@
 
 use std::collections::BTreeMap;
 use std::default::Default;
+use std::fmt;
 
-use bitcoin::{Address, OutPoint, SigHashType, Transaction};
+use bitcoin::{Address, Network, OutPoint, Script, SigHashType, Transaction, TxOut};
 
 use super::coin_selection::{CoinSelectionAlgorithm, DefaultCoinSelectionAlgorithm};
 use crate::types::{FeeRate, UTXO};
 
+/// Standard relay limit (Bitcoin Core's default `-datacarriersize`) on the number of bytes of
+/// data that a single `OP_RETURN` output may carry.
+pub const OP_RETURN_MAX_DATA_SIZE: usize = 80;
+
 #[derive(Debug, Default)]
 pub struct TxBuilder<Cs: CoinSelectionAlgorithm> {
     pub(crate) recipients: Vec<(Address, u64)>,
@@ -38,16 +43,31 @@ pub struct TxBuilder<Cs: CoinSelectionAlgorithm> {
     pub(crate) policy_path: Option<BTreeMap<String, Vec<usize>>>,
     pub(crate) utxos: Option<Vec<OutPoint>>,
     pub(crate) unspendable: Option<Vec<OutPoint>>,
+    /// Fallback sighash applied to inputs that don't have a more specific entry in
+    /// `utxo_sighash`.
     pub(crate) sighash: Option<SigHashType>,
+    pub(crate) utxo_sighash: BTreeMap<OutPoint, SigHashType>,
     pub(crate) ordering: TxOrdering,
     pub(crate) locktime: Option<u32>,
     pub(crate) rbf: Option<u32>,
     pub(crate) version: Option<Version>,
     pub(crate) change_policy: ChangeSpendPolicy,
     pub(crate) force_non_witness_utxo: bool,
+    pub(crate) data: Vec<Vec<u8>>,
+    pub(crate) allow_multiple_op_return: bool,
+    /// Outputs from a [`build_fee_bump`](TxBuilder::build_fee_bump) source transaction whose
+    /// `script_pubkey` isn't a recipient address (e.g. `OP_RETURN`, bare multisig, or any other
+    /// non-standard script). Carried verbatim so a replacement reproduces every original output.
+    pub(crate) other_outputs: Vec<TxOut>,
+    /// The lowest absolute fee, in satoshis, the built transaction must pay. Used by
+    /// [`build_fee_bump`](TxBuilder::build_fee_bump) to enforce BIP125's replacement fee rules.
+    pub(crate) min_fee: Option<u64>,
     pub(crate) coin_selection: Cs,
 }
 
+/// Bitcoin Core's default `-minrelaytxfee`, in satoshis per vbyte.
+const MIN_RELAY_FEE_RATE_SAT_PER_VB: u64 = 1;
+
 impl TxBuilder<DefaultCoinSelectionAlgorithm> {
     pub fn new() -> Self {
         Self::default()
@@ -56,6 +76,70 @@ impl TxBuilder<DefaultCoinSelectionAlgorithm> {
     pub fn with_recipients(recipients: Vec<(Address, u64)>) -> Self {
         Self::default().set_recipients(recipients)
     }
+
+    /// Build a replacement for `original`, an already-broadcast transaction that paid
+    /// `original_fee` satoshis in fees. Reuses `original`'s inputs (more can still be pulled in
+    /// through [`add_utxo`](TxBuilder::add_utxo) if the bumped fee needs extra value), reproduces
+    /// every one of its outputs, `nLockTime` and version, and requires the final fee to be at
+    /// least `original_fee` plus the minimum relay fee (BIP125 rules 3/4).
+    ///
+    /// Outputs are split into [`recipients`](Self::recipients) where the `script_pubkey` decodes
+    /// to an [`Address`] and [`other_outputs`](Self::other_outputs) (verbatim `TxOut`s) where it
+    /// doesn't — e.g. an `OP_RETURN` memo added through [`add_data`](Self::add_data) on the
+    /// original transaction, or a bare multisig output. Either way nothing is dropped: a
+    /// fee-bumped replacement must still pay out exactly what the original did.
+    ///
+    /// `min_fee`'s relay-fee component is an approximation based on `original`'s size, not the
+    /// replacement's: the final input/output set (and therefore the true relay-fee floor) isn't
+    /// known until the replacement is built, and pulling in extra UTXOs via
+    /// [`add_utxo`](Self::add_utxo) only grows the replacement past this estimate, never shrinks
+    /// it. There is also no support yet for shrinking a change output to absorb the bumped fee —
+    /// the extra fee must come from new inputs.
+    ///
+    /// Errors if `original` did not signal replaceability (no input has a sequence number below
+    /// `0xFFFFFFFE`).
+    pub fn build_fee_bump(
+        original: &Transaction,
+        original_fee: u64,
+        network: Network,
+    ) -> Result<Self, TxBuilderError> {
+        let rbf_sequence = original
+            .input
+            .iter()
+            .map(|txin| txin.sequence)
+            .filter(|&sequence| sequence < 0xFFFFFFFE)
+            .min()
+            .ok_or(TxBuilderError::NotReplaceable)?;
+
+        let mut recipients = Vec::new();
+        let mut other_outputs = Vec::new();
+        for txout in &original.output {
+            match Address::from_script(&txout.script_pubkey, network) {
+                Some(address) => recipients.push((address, txout.value)),
+                None => other_outputs.push(txout.clone()),
+            }
+        }
+
+        let original_vsize = (original.get_weight() as u64 + 3) / 4;
+        let min_relay_fee = original_vsize * MIN_RELAY_FEE_RATE_SAT_PER_VB;
+
+        Ok(Self {
+            recipients,
+            other_outputs,
+            utxos: Some(
+                original
+                    .input
+                    .iter()
+                    .map(|txin| txin.previous_output)
+                    .collect(),
+            ),
+            locktime: Some(original.lock_time),
+            version: Some(Version(original.version as u32)),
+            rbf: Some(rbf_sequence),
+            min_fee: Some(original_fee + min_relay_fee),
+            ..Default::default()
+        })
+    }
 }
 
 impl<Cs: CoinSelectionAlgorithm> TxBuilder<Cs> {
@@ -96,6 +180,14 @@ impl<Cs: CoinSelectionAlgorithm> TxBuilder<Cs> {
         self
     }
 
+    /// Like [`add_utxo`](Self::add_utxo), but records a [`SigHashType`] for this specific
+    /// input's `PSBT_IN_SIGHASH_TYPE`, overriding the builder-wide [`sighash`](Self::sighash)
+    /// default for this input only.
+    pub fn add_utxo_with_sighash(mut self, utxo: OutPoint, sighash: SigHashType) -> Self {
+        self.utxo_sighash.insert(utxo, sighash);
+        self.add_utxo(utxo)
+    }
+
     pub fn unspendable(mut self, unspendable: Vec<OutPoint>) -> Self {
         self.unspendable = Some(unspendable);
         self
@@ -106,6 +198,13 @@ impl<Cs: CoinSelectionAlgorithm> TxBuilder<Cs> {
         self
     }
 
+    /// Set the fallback [`SigHashType`] used for inputs that don't have a more specific
+    /// override from [`add_utxo_with_sighash`](Self::add_utxo_with_sighash).
+    ///
+    /// A `SigHashType` is a property of how an *input* is signed (it's carried in the PSBT as
+    /// `PSBT_IN_SIGHASH_TYPE`); outputs have no equivalent. There is deliberately no
+    /// `add_recipient_with_sighash` counterpart for this reason — only the per-input variant is
+    /// provided.
     pub fn sighash(mut self, sighash: SigHashType) -> Self {
         self.sighash = Some(sighash);
         self
@@ -155,6 +254,56 @@ impl<Cs: CoinSelectionAlgorithm> TxBuilder<Cs> {
         self
     }
 
+    /// Add a provably-unspendable `OP_RETURN` output carrying `data`.
+    ///
+    /// At most one data-carrier output is allowed unless [`allow_multiple_op_return`] is
+    /// called, and `data` must not exceed [`OP_RETURN_MAX_DATA_SIZE`] bytes, matching the
+    /// standard relay policy. These outputs are always zero-value and are pinned in place
+    /// (rather than reordered) by [`TxOrdering::Shuffle`].
+    ///
+    /// This builder only records the requested data carriers; it's up to whatever assembles
+    /// the final transaction from [`op_return_outputs`](Self::op_return_outputs) to exclude
+    /// them from `send_all`'s value distribution and to count them towards the transaction's
+    /// weight for fee estimation, the same way it already must for change outputs.
+    ///
+    /// [`allow_multiple_op_return`]: Self::allow_multiple_op_return
+    pub fn add_data(mut self, data: Vec<u8>) -> Result<Self, TxBuilderError> {
+        if data.len() > OP_RETURN_MAX_DATA_SIZE {
+            return Err(TxBuilderError::OpReturnTooLarge {
+                size: data.len(),
+                max: OP_RETURN_MAX_DATA_SIZE,
+            });
+        }
+        if !self.data.is_empty() && !self.allow_multiple_op_return {
+            return Err(TxBuilderError::MultipleOpReturnOutputs);
+        }
+
+        self.data.push(data);
+        Ok(self)
+    }
+
+    /// Alias for [`add_data`](Self::add_data).
+    pub fn add_op_return(self, data: Vec<u8>) -> Result<Self, TxBuilderError> {
+        self.add_data(data)
+    }
+
+    /// Allow more than one `OP_RETURN` output to be added with [`add_data`](Self::add_data).
+    pub fn allow_multiple_op_return(mut self) -> Self {
+        self.allow_multiple_op_return = true;
+        self
+    }
+
+    /// The zero-value `OP_RETURN` outputs requested through [`add_data`](Self::add_data),
+    /// ready to be appended to the transaction alongside the recipient/change outputs.
+    pub(crate) fn op_return_outputs(&self) -> impl Iterator<Item = TxOut> + '_ {
+        self.data
+            .iter()
+            .map(|data| TxOut {
+                value: 0,
+                script_pubkey: Script::new_op_return(data),
+            })
+    }
+
     pub fn coin_selection<P: CoinSelectionAlgorithm>(self, coin_selection: P) -> TxBuilder<P> {
         TxBuilder {
             recipients: self.recipients,
@@ -164,20 +313,64 @@ impl<Cs: CoinSelectionAlgorithm> TxBuilder<Cs> {
             utxos: self.utxos,
             unspendable: self.unspendable,
             sighash: self.sighash,
+            utxo_sighash: self.utxo_sighash,
             ordering: self.ordering,
             locktime: self.locktime,
             rbf: self.rbf,
             version: self.version,
             change_policy: self.change_policy,
             force_non_witness_utxo: self.force_non_witness_utxo,
+            data: self.data,
+            allow_multiple_op_return: self.allow_multiple_op_return,
+            other_outputs: self.other_outputs,
+            min_fee: self.min_fee,
             coin_selection,
         }
     }
 }
 
+/// Errors that can occur while configuring a [`TxBuilder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxBuilderError {
+    /// The pushed `OP_RETURN` data exceeds the standard relay limit.
+    OpReturnTooLarge { size: usize, max: usize },
+    /// A second `OP_RETURN` output was added without calling
+    /// [`allow_multiple_op_return`](TxBuilder::allow_multiple_op_return).
+    MultipleOpReturnOutputs,
+    /// [`build_fee_bump`](TxBuilder::build_fee_bump) was called on a transaction that didn't
+    /// signal BIP125 replaceability.
+    NotReplaceable,
+}
+
+impl fmt::Display for TxBuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxBuilderError::OpReturnTooLarge { size, max } => write!(
+                f,
+                "OP_RETURN data is {} bytes, which exceeds the {}-byte standard relay limit",
+                size, max
+            ),
+            TxBuilderError::MultipleOpReturnOutputs => write!(
+                f,
+                "only one OP_RETURN output is allowed unless `allow_multiple_op_return` is set"
+            ),
+            TxBuilderError::NotReplaceable => write!(
+                f,
+                "the original transaction did not signal BIP125 replaceability"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TxBuilderError {}
+
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
 pub enum TxOrdering {
     Shuffle,
+    /// Like [`Shuffle`](Self::Shuffle), but seeds the shuffle from `seed` instead of system
+    /// entropy, so the resulting output order is reproducible (deterministic PSBT round-trips,
+    /// hardware-wallet display verification, regression fixtures, ...).
+    ShuffleWith(u64),
     Untouched,
     BIP69Lexicographic,
 }
@@ -193,16 +386,20 @@ impl TxOrdering {
         match self {
             TxOrdering::Untouched => {}
             TxOrdering::Shuffle => {
-                use rand::seq::SliceRandom;
                 #[cfg(test)]
                 use rand::SeedableRng;
 
                 #[cfg(not(test))]
-                let mut rng = rand::thread_rng();
+                let rng = rand::thread_rng();
                 #[cfg(test)]
-                let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+                let rng = rand::rngs::StdRng::seed_from_u64(0);
 
-                tx.output.shuffle(&mut rng);
+                Self::shuffle_movable_outputs(tx, rng);
+            }
+            TxOrdering::ShuffleWith(seed) => {
+                use rand::SeedableRng;
+
+                Self::shuffle_movable_outputs(tx, rand::rngs::StdRng::seed_from_u64(*seed));
             }
             TxOrdering::BIP69Lexicographic => {
                 tx.input.sort_unstable_by_key(|txin| {
@@ -213,6 +410,22 @@ impl TxOrdering {
             }
         }
     }
+
+    /// Shuffle `tx`'s outputs using `rng`, leaving `OP_RETURN` data-carrier outputs at their
+    /// original index: shuffling them among the recipient outputs doesn't change standardness,
+    /// but it would break any caller that locates the memo by its output index.
+    fn shuffle_movable_outputs(tx: &mut Transaction, mut rng: impl rand::RngCore) {
+        use rand::seq::SliceRandom;
+
+        let movable: Vec<usize> = (0..tx.output.len())
+            .filter(|&i| !tx.output[i].script_pubkey.is_op_return())
+            .collect();
+        let mut shuffled_outputs: Vec<_> = movable.iter().map(|&i| tx.output[i].clone()).collect();
+        shuffled_outputs.shuffle(&mut rng);
+        for (&i, txout) in movable.iter().zip(shuffled_outputs) {
+            tx.output[i] = txout;
+        }
+    }
 }
 
 // Helper type that wraps u32 and has a default value of 1
@@ -294,6 +507,91 @@ mod test {
         assert_ne!(original_tx.output, tx.output);
     }
 
+    #[test]
+    fn test_output_ordering_shuffle_with_is_deterministic() {
+        let original_tx = ordering_test_tx!();
+
+        let mut tx_a = original_tx.clone();
+        TxOrdering::ShuffleWith(42).sort_tx(&mut tx_a);
+
+        let mut tx_b = original_tx.clone();
+        TxOrdering::ShuffleWith(42).sort_tx(&mut tx_b);
+
+        assert_eq!(tx_a.output, tx_b.output);
+    }
+
+    #[test]
+    fn test_output_ordering_shuffle_keeps_op_return_in_place() {
+        let original_tx = ordering_test_tx!();
+        let mut tx = original_tx.clone();
+        tx.output.push(TxOut {
+            value: 0,
+            script_pubkey: Script::new_op_return(&[0xde, 0xad, 0xbe, 0xef]),
+        });
+        let op_return_index = tx.output.len() - 1;
+        let op_return_before = tx.output[op_return_index].clone();
+
+        TxOrdering::Shuffle.sort_tx(&mut tx);
+
+        assert_eq!(tx.output[op_return_index], op_return_before);
+    }
+
+    #[test]
+    fn test_add_utxo_with_sighash_overrides_default() {
+        let outpoint = OutPoint {
+            txid: Default::default(),
+            vout: 0,
+        };
+        let builder = TxBuilder::<DefaultCoinSelectionAlgorithm>::new()
+            .sighash(SigHashType::All)
+            .add_utxo_with_sighash(outpoint, SigHashType::SinglePlusAnyoneCanPay);
+
+        assert_eq!(builder.sighash, Some(SigHashType::All));
+        assert_eq!(
+            builder.utxo_sighash.get(&outpoint),
+            Some(&SigHashType::SinglePlusAnyoneCanPay)
+        );
+        assert_eq!(builder.utxos, Some(vec![outpoint]));
+    }
+
+    #[test]
+    fn test_add_data_rejects_oversized_payload() {
+        let builder = TxBuilder::<DefaultCoinSelectionAlgorithm>::new();
+        let result = builder.add_data(vec![0; OP_RETURN_MAX_DATA_SIZE + 1]);
+
+        assert_eq!(
+            result.unwrap_err(),
+            TxBuilderError::OpReturnTooLarge {
+                size: OP_RETURN_MAX_DATA_SIZE + 1,
+                max: OP_RETURN_MAX_DATA_SIZE,
+            }
+        );
+    }
+
+    #[test]
+    fn test_add_data_rejects_second_op_return_by_default() {
+        let builder = TxBuilder::<DefaultCoinSelectionAlgorithm>::new()
+            .add_data(vec![1, 2, 3])
+            .unwrap();
+
+        assert_eq!(
+            builder.add_data(vec![4, 5, 6]).unwrap_err(),
+            TxBuilderError::MultipleOpReturnOutputs
+        );
+    }
+
+    #[test]
+    fn test_add_data_allows_multiple_when_opted_in() {
+        let builder = TxBuilder::<DefaultCoinSelectionAlgorithm>::new()
+            .allow_multiple_op_return()
+            .add_data(vec![1, 2, 3])
+            .unwrap()
+            .add_data(vec![4, 5, 6])
+            .unwrap();
+
+        assert_eq!(builder.op_return_outputs().count(), 2);
+    }
+
     #[test]
     fn test_output_ordering_bip69() {
         use std::str::FromStr;
@@ -382,4 +680,85 @@ mod test {
         let version = Version::default();
         assert_eq!(version.0, 1);
     }
+
+    fn replaceable_test_tx() -> Transaction {
+        use bitcoin::{TxIn, TxOut};
+
+        Transaction {
+            version: 2,
+            lock_time: 100,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: Default::default(),
+                    vout: 0,
+                },
+                script_sig: Script::new(),
+                sequence: 0xFFFFFFFD,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 50_000,
+                script_pubkey: Address::p2pkh(
+                    &bitcoin::PublicKey::from_private_key(
+                        &bitcoin::secp256k1::Secp256k1::new(),
+                        &bitcoin::PrivateKey {
+                            compressed: true,
+                            network: Network::Testnet,
+                            key: bitcoin::secp256k1::SecretKey::from_slice(&[1; 32]).unwrap(),
+                        },
+                    ),
+                    Network::Testnet,
+                )
+                .script_pubkey(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_build_fee_bump_rejects_non_replaceable_tx() {
+        let original_tx = ordering_test_tx!();
+
+        let result =
+            TxBuilder::<DefaultCoinSelectionAlgorithm>::build_fee_bump(&original_tx, 500, Network::Testnet);
+
+        assert_eq!(result.unwrap_err(), TxBuilderError::NotReplaceable);
+    }
+
+    #[test]
+    fn test_build_fee_bump_preserves_inputs_and_locktime() {
+        let original_tx = replaceable_test_tx();
+
+        let builder =
+            TxBuilder::<DefaultCoinSelectionAlgorithm>::build_fee_bump(&original_tx, 500, Network::Testnet)
+                .unwrap();
+
+        assert_eq!(
+            builder.utxos,
+            Some(vec![original_tx.input[0].previous_output])
+        );
+        assert_eq!(builder.locktime, Some(100));
+        assert_eq!(builder.version, Some(Version(2)));
+        assert_eq!(builder.recipients.len(), 1);
+        assert_eq!(builder.recipients[0].1, 50_000);
+        assert!(builder.min_fee.unwrap() > 500);
+    }
+
+    #[test]
+    fn test_build_fee_bump_preserves_non_address_outputs() {
+        use bitcoin::TxOut;
+
+        let mut original_tx = replaceable_test_tx();
+        let op_return_output = TxOut {
+            value: 0,
+            script_pubkey: Script::new_op_return(&[0xde, 0xad, 0xbe, 0xef]),
+        };
+        original_tx.output.push(op_return_output.clone());
+
+        let builder =
+            TxBuilder::<DefaultCoinSelectionAlgorithm>::build_fee_bump(&original_tx, 500, Network::Testnet)
+                .unwrap();
+
+        assert_eq!(builder.recipients.len(), 1);
+        assert_eq!(builder.other_outputs, vec![op_return_output]);
+    }
 }